@@ -6,8 +6,129 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
 use toml;
 
+// Expands a leading `~` to the user's home directory. Unix-only, like the
+// rest of this module's environment-variable handling: reads `$HOME`
+// rather than using `env::home_dir()`, whose behaviour is unreliable
+// enough (notably on Windows) that the standard library deprecated it.
+fn expand_home(value: &str) -> String {
+    let home = match env::var("HOME") {
+        Ok(home) => home,
+        Err(_) => return value.to_owned(),
+    };
+    if value == "~" {
+        return home;
+    }
+    if value.starts_with("~/") {
+        return format!("{}{}", home, &value[1..]);
+    }
+    value.to_owned()
+}
+
+// Substitutes `$VAR`/`${VAR}` with the named environment variable. A name
+// that isn't set in the environment (e.g. the `$file`/`$line`/`$col`
+// placeholders in `edit_command`) is left untouched, rather than being
+// replaced with an empty string.
+fn expand_env_vars(value: &str) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    let mut result = String::with_capacity(value.len());
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] != '$' || i + 1 >= chars.len() {
+            result.push(chars[i]);
+            i += 1;
+            continue;
+        }
+
+        if chars[i + 1] == '{' {
+            match chars[i..].iter().position(|&c| c == '}') {
+                Some(end) => {
+                    let name: String = chars[i + 2..i + end].iter().collect();
+                    match env::var(&name) {
+                        Ok(val) => result.push_str(&val),
+                        Err(_) => result.push_str(&chars[i..i + end + 1].iter().collect::<String>()),
+                    }
+                    i += end + 1;
+                }
+                None => {
+                    result.push(chars[i]);
+                    i += 1;
+                }
+            }
+            continue;
+        }
+
+        if chars[i + 1].is_alphabetic() || chars[i + 1] == '_' {
+            let mut end = i + 1;
+            while end < chars.len() && (chars[end].is_alphanumeric() || chars[end] == '_') {
+                end += 1;
+            }
+            let name: String = chars[i + 1..end].iter().collect();
+            match env::var(&name) {
+                Ok(val) => result.push_str(&val),
+                Err(_) => result.push_str(&chars[i..end].iter().collect::<String>()),
+            }
+            i = end;
+            continue;
+        }
+
+        result.push(chars[i]);
+        i += 1;
+    }
+    result
+}
+
+fn expand_path_like(value: &str) -> String {
+    expand_env_vars(&expand_home(value))
+}
+
+/// Everything that can go wrong while turning a `rustw.toml` into a
+/// `Config`.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file's contents were not valid TOML.
+    Parse(String),
+    /// The TOML parsed, but didn't decode into a `ParsedConfig`.
+    Decode(String),
+    /// The file set one or more keys that don't match any known
+    /// configuration option, e.g. a typo like `contex_lines`.
+    UnknownFields(Vec<String>),
+    /// The config file couldn't be read.
+    Io(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Parse(ref s) => write!(f, "could not parse TOML: {}", s),
+            ConfigError::Decode(ref s) => write!(f, "could not decode config: {}", s),
+            ConfigError::UnknownFields(ref fields) => {
+                write!(f, "unknown configuration option(s): {}", fields.join(", "))
+            }
+            ConfigError::Io(ref s) => write!(f, "could not read config file: {}", s),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn description(&self) -> &str {
+        match *self {
+            ConfigError::Parse(_) => "could not parse TOML",
+            ConfigError::Decode(_) => "could not decode config",
+            ConfigError::UnknownFields(_) => "unknown configuration option(s)",
+            ConfigError::Io(_) => "could not read config file",
+        }
+    }
+}
+
 // Copy-pasta from rustfmt (but using Serde instead of rustc_decode).
 macro_rules! impl_enum_decodable {
     ( $e:ident, $( $x:ident ),* ) => {
@@ -47,6 +168,33 @@ macro_rules! impl_enum_decodable {
                 format!("[{}]", variants.join("|"))
             }
         }
+
+        // So a `configuration_option_enum!` type can also be decoded from a
+        // `rustw.toml` (which still goes through `RustcDecodable`).
+        impl ::rustc_serialize::Decodable for $e {
+            fn decode<D: ::rustc_serialize::Decoder>(d: &mut D) -> Result<Self, D::Error> {
+                use std::ascii::AsciiExt;
+                let s = try!(d.read_str());
+                $(
+                    if stringify!($x).eq_ignore_ascii_case(&s) {
+                        return Ok($e::$x);
+                    }
+                )*
+                Err(d.error("Bad variant"))
+            }
+        }
+
+        impl ::serde::Serialize for $e {
+            fn serialize<S: ::serde::Serializer>(&self, s: &mut S) -> Result<(), S::Error> {
+                let variant = match *self {
+                    $( $e::$x => stringify!($x), )*
+                };
+                s.serialize_str(variant)
+            }
+        }
+
+        // Lets `$e` be set from the CLI layer, same as the primitive types.
+        impl ::config::CliValue for $e {}
     };
 }
 
@@ -85,8 +233,32 @@ impl ConfigType for String {
     }
 }
 
+// Implemented for every type a config option can hold, so
+// `Config::parse_cli_args` can parse a `--field-name value` pair into
+// the right `$ty`.
+pub trait CliValue: ::std::str::FromStr {
+    // Every option accepts `--field-name=value`. Bool options are also set
+    // by a bare `--field-name` (true) or `--no-field-name` (false);
+    // everything else also accepts `--field-name value`.
+    fn negatable() -> bool {
+        false
+    }
+}
+
+impl CliValue for bool {
+    fn negatable() -> bool {
+        true
+    }
+}
+
+impl CliValue for usize {}
+
+impl CliValue for String {}
+
+// `stable` options are always honoured; `unstable` options are only
+// honoured once `unstable_features` is set.
 macro_rules! create_config {
-    ($($i:ident: $ty:ty, $def:expr, $( $dstring:expr ),+ );+ $(;)*) => (
+    ($($i:ident: $ty:ty, $def:expr, $stab:ident, $( $dstring:expr ),+ );+ $(;)*) => (
         #[derive(Serialize, RustcDecodable, Clone)]
         pub struct Config {
             $(pub $i: $ty),+
@@ -102,6 +274,20 @@ macro_rules! create_config {
             $(pub $i: Option<$ty>),+
         }
 
+        impl Default for ParsedConfig {
+            fn default() -> ParsedConfig {
+                ParsedConfig {
+                    $($i: None),+
+                }
+            }
+        }
+
+        // The set of field names the macro generated `Config` and
+        // `ParsedConfig` for, used to spot unknown keys in a `rustw.toml`.
+        pub const FIELD_NAMES: &'static [&'static str] = &[
+            $(stringify!($i)),+
+        ];
+
         impl Config {
 
             fn fill_from_parsed_config(mut self, parsed: ParsedConfig) -> Config {
@@ -113,18 +299,172 @@ macro_rules! create_config {
                 self
             }
 
-            pub fn from_toml(toml: &str) -> Config {
-                let parsed = toml.parse().expect("Could not parse TOML");
-                let parsed_config:ParsedConfig = match toml::decode(parsed) {
-                    Some(decoded) => decoded,
-                    None => {
-                        println!("Decoding config file failed. Config:\n{}", toml);
-                        let parsed: toml::Value = toml.parse().expect("Could not parse TOML");
-                        println!("\n\nParsed:\n{:?}", parsed);
-                        panic!();
+            // Resets any unstable option back to its default unless
+            // `unstable_features` is set. Run once all sources (file, then
+            // CLI) have been merged in, so an `unstable_features` set only
+            // on the CLI still unlocks an unstable option set only in the
+            // file.
+            fn enforce_stability(mut self) -> Config {
+            $(
+                if stringify!($stab) == "unstable" && !self.unstable_features && self.$i != $def {
+                    println!("Warning: can't set `{}`, unstable options require \
+                               `unstable_features = true`",
+                             stringify!($i));
+                    self.$i = $def;
+                }
+            )+
+                self
+            }
+
+            fn parse_toml(toml_str: &str) -> Result<ParsedConfig, ConfigError> {
+                let value: toml::Value = match toml_str.parse() {
+                    Ok(value) => value,
+                    Err(e) => return Err(ConfigError::Parse(format!("{:?}", e))),
+                };
+
+                if let toml::Value::Table(ref table) = value {
+                    let unknown: Vec<String> = table.keys()
+                        .filter(|k| !FIELD_NAMES.contains(&k.as_str()))
+                        .cloned()
+                        .collect();
+                    if !unknown.is_empty() {
+                        return Err(ConfigError::UnknownFields(unknown));
+                    }
+                }
+
+                match toml::decode(value) {
+                    Some(decoded) => Ok(decoded),
+                    None => Err(ConfigError::Decode(format!("{}", toml_str))),
+                }
+            }
+
+            pub fn from_toml(toml_str: &str) -> Result<Config, ConfigError> {
+                Config::parse_toml(toml_str).map(|parsed| {
+                    Config::default()
+                        .fill_from_parsed_config(parsed)
+                        .enforce_stability()
+                        .normalize()
+                })
+            }
+
+            // Expands `~` and `$VAR`/`${VAR}` in the path/command options,
+            // run once the CLI and file values have been merged in. Unset
+            // variables (like the `$file`/`$line`/`$col` placeholders in
+            // `edit_command`) are left as-is.
+            fn normalize(mut self) -> Config {
+                self.source_directory = expand_path_like(&self.source_directory);
+                self.demo_mode_root_path = expand_path_like(&self.demo_mode_root_path);
+                self.edit_command = expand_path_like(&self.edit_command);
+                self
+            }
+
+            // Scans `args` (normally `env::args().skip(1).collect::<Vec<_>>()`)
+            // for `--field-name value`, `--field-name=value` and, for bool
+            // options, the negated `--no-field-name` form. Unrecognised
+            // flags and values that fail to parse are left as `None`, so
+            // they fall back to the file value (or the default) once
+            // merged with `fill_from_parsed_config`.
+            pub fn parse_cli_args(args: &[String]) -> ParsedConfig {
+                let mut parsed = ParsedConfig::default();
+                let mut args = args.iter();
+                while let Some(arg) = args.next() {
+                    $(
+                        let flag = concat!("--", stringify!($i)).replace('_', "-");
+                        let eq_prefix = format!("{}=", flag);
+                        if <$ty as CliValue>::negatable() {
+                            let no_flag = format!("--no-{}", &flag[2..]);
+                            if *arg == no_flag {
+                                if let Ok(val) = "false".parse::<$ty>() {
+                                    parsed.$i = Some(val);
+                                }
+                                continue;
+                            }
+                        }
+                        if arg.starts_with(&eq_prefix) {
+                            if let Ok(val) = arg[eq_prefix.len()..].parse::<$ty>() {
+                                parsed.$i = Some(val);
+                            }
+                            continue;
+                        }
+                        if *arg == flag {
+                            if <$ty as CliValue>::negatable() {
+                                if let Ok(val) = "true".parse::<$ty>() {
+                                    parsed.$i = Some(val);
+                                }
+                            } else if let Some(value) = args.next() {
+                                if let Ok(val) = value.parse::<$ty>() {
+                                    parsed.$i = Some(val);
+                                }
+                            }
+                            continue;
+                        }
+                    )+
+                }
+                parsed
+            }
+
+            // Finds an explicit `--config <path>` (or `--config=<path>`) in
+            // `args`, if the user passed one.
+            fn explicit_config_path(args: &[String]) -> Option<PathBuf> {
+                let mut args = args.iter();
+                while let Some(arg) = args.next() {
+                    if arg == "--config" {
+                        return args.next().map(PathBuf::from);
+                    }
+                    if arg.starts_with("--config=") {
+                        return Some(PathBuf::from(&arg["--config=".len()..]));
                     }
+                }
+                None
+            }
+
+            // Walks from `start_dir` up through its ancestors, returning
+            // the first `rustw.toml` found.
+            fn find_config_file(start_dir: &Path) -> Option<PathBuf> {
+                let mut dir = Some(start_dir);
+                while let Some(d) = dir {
+                    let candidate = d.join("rustw.toml");
+                    if candidate.is_file() {
+                        return Some(candidate);
+                    }
+                    dir = d.parent();
+                }
+                None
+            }
+
+            fn read_config_file(path: &Path) -> Result<String, ConfigError> {
+                let mut file = try!(File::open(path).map_err(|e| ConfigError::Io(format!("{}", e))));
+                let mut contents = String::new();
+                try!(file.read_to_string(&mut contents).map_err(|e| ConfigError::Io(format!("{}", e))));
+                Ok(contents)
+            }
+
+            // Resolves a `Config` with the documented precedence: CLI flags
+            // win over the `rustw.toml` found by walking up from
+            // `start_dir` (or the file named by an explicit `--config`),
+            // which in turn wins over `Config::default()`. Returns the
+            // path of the config file that was used, if any.
+            pub fn resolve(start_dir: &Path,
+                            args: &[String])
+                            -> Result<(Config, Option<PathBuf>), ConfigError> {
+                let config_path = Config::explicit_config_path(args)
+                    .or_else(|| Config::find_config_file(start_dir));
+
+                let from_file = match config_path {
+                    Some(ref path) => {
+                        let toml_str = try!(Config::read_config_file(path));
+                        try!(Config::parse_toml(&toml_str))
+                    }
+                    None => ParsedConfig::default(),
                 };
-                Config::default().fill_from_parsed_config(parsed_config)
+
+                let config = Config::default()
+                    .fill_from_parsed_config(from_file)
+                    .fill_from_parsed_config(Config::parse_cli_args(args))
+                    .enforce_stability()
+                    .normalize();
+
+                Ok((config, config_path))
             }
 
             pub fn print_docs() {
@@ -144,10 +484,12 @@ macro_rules! create_config {
                     }
                     name_out.push_str(name_raw);
                     name_out.push(' ');
-                    println!("{}{} Default: {:?}",
+                    println!("{}{} Default: {:?}{}",
                              name_out,
                              <$ty>::get_variant_names(),
-                             $def);
+                             $def,
+                             if stringify!($stab) == "unstable" { " (unstable)" } else { "" });
+                    println!("{}--{}", space_str, name_raw.replace('_', "-"));
                     $(
                         println!("{}{}", space_str, $dstring);
                     )+
@@ -169,14 +511,222 @@ macro_rules! create_config {
     )
 }
 
+// The build system rustw should invoke on a build request. `Shell` runs
+// `build_command` verbatim, while `Cargo` and `Make` pick their own
+// well-known invocation and ignore `build_command`.
+configuration_option_enum! { BuildSystem: Cargo, Make, Shell }
+
 create_config! {
-    build_command: String, "cargo build".to_owned(), "command to call to build";
-    edit_command: String, String::new(), "command to call to edit; can use $file, $line, and $col.";
-    port: usize, 7878, "port to run rustw on";
-    demo_mode: bool, false, "run in demo mode";
-    demo_mode_root_path: String, String::new(), "path to use in URLs in demo mode";
-    context_lines: usize, 2, "lines of context to show before and after code snippets";
-    build_on_load: bool, true, "build on page load and refresh";
-    source_directory: String, "src".to_owned(), "root of the source directory";
-    save_analysis: bool, false, "whether to run the save_analysis pass";
+    unstable_features: bool, false, stable, "enables unstable configuration options";
+    build_command: String, "cargo build".to_owned(), stable, "command to call to build";
+    build_system: BuildSystem, BuildSystem::Cargo, stable, "build system to use; Cargo and Make pick their own invocation, Shell runs `build_command` verbatim";
+    edit_command: String, String::new(), stable, "command to call to edit; can use $file, $line, and $col.";
+    port: usize, 7878, stable, "port to run rustw on";
+    demo_mode: bool, false, stable, "run in demo mode";
+    demo_mode_root_path: String, String::new(), stable, "path to use in URLs in demo mode";
+    context_lines: usize, 2, stable, "lines of context to show before and after code snippets";
+    build_on_load: bool, true, stable, "build on page load and refresh";
+    source_directory: String, "src".to_owned(), stable, "root of the source directory";
+    save_analysis: bool, false, unstable, "whether to run the save_analysis pass";
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_cli_args_flag_equals_value() {
+        let args = vec!["--port=1234".to_owned()];
+        let parsed = Config::parse_cli_args(&args);
+        assert_eq!(parsed.port, Some(1234));
+    }
+
+    #[test]
+    fn parse_cli_args_flag_space_value() {
+        let args = vec!["--port".to_owned(), "1234".to_owned()];
+        let parsed = Config::parse_cli_args(&args);
+        assert_eq!(parsed.port, Some(1234));
+    }
+
+    #[test]
+    fn parse_cli_args_no_flag() {
+        let args = vec!["--no-build-on-load".to_owned()];
+        let parsed = Config::parse_cli_args(&args);
+        assert_eq!(parsed.build_on_load, Some(false));
+    }
+
+    #[test]
+    fn parse_cli_args_bare_flag_sets_bool_true() {
+        let args = vec!["--build-on-load".to_owned()];
+        let parsed = Config::parse_cli_args(&args);
+        assert_eq!(parsed.build_on_load, Some(true));
+    }
+
+    #[test]
+    fn parse_cli_args_unknown_flag_is_ignored() {
+        let args = vec!["--no-such-flag".to_owned(), "value".to_owned()];
+        let parsed = Config::parse_cli_args(&args);
+        assert_eq!(parsed.port, None);
+        assert_eq!(parsed.build_command, None);
+    }
+
+    #[test]
+    fn parse_cli_args_missing_value_is_ignored() {
+        let args = vec!["--port".to_owned()];
+        let parsed = Config::parse_cli_args(&args);
+        assert_eq!(parsed.port, None);
+    }
+
+    #[test]
+    fn build_system_from_str_is_case_insensitive() {
+        assert_eq!("cargo".parse::<BuildSystem>(), Ok(BuildSystem::Cargo));
+        assert_eq!("Make".parse::<BuildSystem>(), Ok(BuildSystem::Make));
+        assert_eq!("SHELL".parse::<BuildSystem>(), Ok(BuildSystem::Shell));
+    }
+
+    #[test]
+    fn build_system_from_str_rejects_unknown_variant() {
+        assert!("docker".parse::<BuildSystem>().is_err());
+    }
+
+    #[test]
+    fn parse_cli_args_sets_build_system() {
+        let args = vec!["--build-system".to_owned(), "Make".to_owned()];
+        let parsed = Config::parse_cli_args(&args);
+        assert_eq!(parsed.build_system, Some(BuildSystem::Make));
+    }
+
+    #[test]
+    fn from_toml_rejects_unknown_build_system_variant() {
+        assert!(Config::from_toml("build_system = \"docker\"\n").is_err());
+    }
+
+    #[test]
+    fn enforce_stability_resets_unstable_option_without_opt_in() {
+        let mut parsed = ParsedConfig::default();
+        parsed.save_analysis = Some(true);
+
+        let config = Config::default().fill_from_parsed_config(parsed).enforce_stability();
+
+        assert_eq!(config.save_analysis, false);
+    }
+
+    #[test]
+    fn from_toml_rejects_unknown_fields() {
+        match Config::from_toml("contex_lines = 4\n") {
+            Err(ConfigError::UnknownFields(fields)) => {
+                assert_eq!(fields, vec!["contex_lines".to_owned()]);
+            }
+            _ => panic!("expected ConfigError::UnknownFields"),
+        }
+    }
+
+    #[test]
+    fn from_toml_decodes_known_fields() {
+        let config = Config::from_toml("port = 1234\n").unwrap();
+        assert_eq!(config.port, 1234);
+    }
+
+    #[test]
+    fn find_config_file_walks_up_ancestors() {
+        use std::fs;
+        use std::process;
+
+        let base = env::temp_dir().join(format!("rustw-test-{}-ancestors", process::id()));
+        let nested = base.join("a").join("b");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(base.join("rustw.toml"), "port = 4242\n").unwrap();
+
+        assert_eq!(Config::find_config_file(&nested), Some(base.join("rustw.toml")));
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn find_config_file_returns_none_when_absent() {
+        use std::fs;
+        use std::process;
+
+        let base = env::temp_dir().join(format!("rustw-test-{}-none", process::id()));
+        fs::create_dir_all(&base).unwrap();
+
+        assert_eq!(Config::find_config_file(&base), None);
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn explicit_config_path_is_read_from_args() {
+        let args = vec!["--config".to_owned(), "/some/explicit/path.toml".to_owned()];
+        assert_eq!(Config::explicit_config_path(&args),
+                   Some(PathBuf::from("/some/explicit/path.toml")));
+    }
+
+    #[test]
+    fn resolve_uses_explicit_config_path_over_ancestor_walk() {
+        use std::fs;
+        use std::process;
+
+        let base = env::temp_dir().join(format!("rustw-test-{}-resolve", process::id()));
+        fs::create_dir_all(&base).unwrap();
+        let explicit = base.join("explicit.toml");
+        fs::write(&explicit, "port = 4242\n").unwrap();
+
+        // No `rustw.toml` in `base`, so an ancestor walk from here would
+        // find nothing; only the explicit `--config` should be used.
+        let args = vec!["--config".to_owned(), explicit.display().to_string()];
+        let (config, used_path) = Config::resolve(&base, &args).unwrap();
+
+        assert_eq!(config.port, 4242);
+        assert_eq!(used_path, Some(explicit.clone()));
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn enforce_stability_honors_unstable_option_with_opt_in() {
+        let mut parsed = ParsedConfig::default();
+        parsed.save_analysis = Some(true);
+        parsed.unstable_features = Some(true);
+
+        let config = Config::default().fill_from_parsed_config(parsed).enforce_stability();
+
+        assert_eq!(config.save_analysis, true);
+    }
+
+    // A single test function for each mutated var, rather than one per
+    // assertion: `cargo test` runs tests in parallel, and two tests
+    // racing a `set_var`/`remove_var` pair on the same name would be
+    // intermittently flaky. The original value is restored afterwards so
+    // the rest of the test binary doesn't see a clobbered environment.
+    #[test]
+    fn expand_env_vars_substitutes_known_vars_and_leaves_others() {
+        let original = env::var("RUSTW_TEST_VAR").ok();
+        env::set_var("RUSTW_TEST_VAR", "value");
+
+        assert_eq!(expand_env_vars("$RUSTW_TEST_VAR/foo"), "value/foo");
+        assert_eq!(expand_env_vars("${RUSTW_TEST_VAR}bar"), "valuebar");
+        assert_eq!(expand_env_vars("$RUSTW_NO_SUCH_VAR"), "$RUSTW_NO_SUCH_VAR");
+        assert_eq!(expand_env_vars("$file:$line:$col"), "$file:$line:$col");
+
+        match original {
+            Some(val) => env::set_var("RUSTW_TEST_VAR", val),
+            None => env::remove_var("RUSTW_TEST_VAR"),
+        }
+    }
+
+    #[test]
+    fn expand_home_substitutes_tilde_and_leaves_other_paths() {
+        let original = env::var("HOME").ok();
+        env::set_var("HOME", "/home/rustw");
+
+        assert_eq!(expand_home("~"), "/home/rustw");
+        assert_eq!(expand_home("~/projects/foo"), "/home/rustw/projects/foo");
+        assert_eq!(expand_home("/absolute/path"), "/absolute/path");
+
+        match original {
+            Some(val) => env::set_var("HOME", val),
+            None => env::remove_var("HOME"),
+        }
+    }
 }